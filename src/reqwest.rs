@@ -1,6 +1,10 @@
 //! # Reqwest-based EventSource client
 
 extern crate reqwest as reqw;
+extern crate futures;
+extern crate tokio;
+extern crate bytes;
+extern crate rand;
 
 mod errors {
     error_chain! {
@@ -24,18 +28,335 @@ mod errors {
                 description("no Content-Type header in response")
                 display("Content-Type missing")
             }
+
+            EventTooLarge {
+                description("line or event exceeded the configured size limit")
+                display("line or event exceeded the configured size limit")
+            }
+
+            Aborted {
+                description("client was aborted via its AbortHandle")
+                display("client was aborted")
+            }
+
+            InvalidRedirect {
+                description("redirect response was missing a usable Location header")
+                display("redirect response was missing a usable Location header")
+            }
+
+            TooManyRedirects {
+                description("exceeded the maximum number of redirects")
+                display("exceeded the maximum number of redirects")
+            }
         }
     }
 }
 pub use self::errors::*;
 
+use std::fs;
 use std::io::{BufRead, BufReader};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 use super::event::{Event, ParseResult, parse_event_line};
 use self::reqw::header::{Headers, Accept, ContentType, qitem};
 use self::reqw::mime;
+use self::futures::Stream;
+use self::reqw::header::RetryAfter;
+use self::tokio::time::{delay_for, Delay};
+
+/// A cloneable handle that can stop a [`Client`](struct.Client.html) or
+/// [`AsyncClient`](struct.AsyncClient.html) from another thread or task.
+///
+/// Once aborted, the client stops as soon as it next checks the handle: between reconnection
+/// attempts, or between lines of a response that's currently being read.
+#[derive(Debug, Clone)]
+pub struct AbortHandle(Arc<AtomicBool>);
+
+impl AbortHandle {
+    fn new() -> AbortHandle {
+        AbortHandle(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Signals the client to stop.
+    pub fn abort(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Persists the `Last-Event-ID` so a resumable stream survives a process restart.
+///
+/// `next_request` seeds the `Last-Event-ID` header from `load()` on the very first connection,
+/// and every dispatched event that carries an `id` is handed to `store()` afterward.
+///
+/// `Send` is required so a `Box<dyn EventIdStore>` doesn't make `AsyncClient` itself non-`Send`,
+/// which would rule out spawning it onto a multi-threaded executor.
+pub trait EventIdStore: Send {
+    /// Returns the last stored event ID, if any.
+    fn load(&self) -> Option<String>;
+
+    /// Persists `id` as the new last event ID.
+    fn store(&mut self, id: &str);
+}
+
+/// An `EventIdStore` that only keeps the ID in memory, for the lifetime of the client. This is
+/// the default, and matches the client's previous (non-resumable) behavior.
+#[derive(Debug, Default)]
+pub struct MemoryEventIdStore {
+    id: Option<String>,
+}
+
+impl MemoryEventIdStore {
+    /// Constructs an empty in-memory store.
+    pub fn new() -> MemoryEventIdStore {
+        MemoryEventIdStore { id: None }
+    }
+}
+
+impl EventIdStore for MemoryEventIdStore {
+    fn load(&self) -> Option<String> {
+        self.id.clone()
+    }
+
+    fn store(&mut self, id: &str) {
+        self.id = Some(id.to_owned());
+    }
+}
+
+/// An `EventIdStore` backed by a file, so the resume point survives a process restart.
+pub struct FileEventIdStore {
+    path: PathBuf,
+}
+
+impl FileEventIdStore {
+    /// Constructs a store backed by the file at `path`. The file does not need to exist yet;
+    /// `load()` simply returns `None` until the first `store()` call creates it.
+    pub fn new<P: AsRef<Path>>(path: P) -> FileEventIdStore {
+        FileEventIdStore {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl EventIdStore for FileEventIdStore {
+    fn load(&self) -> Option<String> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                let id = contents.trim();
+                if id.is_empty() {
+                    None
+                } else {
+                    Some(id.to_owned())
+                }
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn store(&mut self, id: &str) {
+        // Best-effort: a failed write just means the next restart replays from the server's
+        // default position, same as if no store were configured at all.
+        let _ = fs::write(&self.path, id);
+    }
+}
 
 const DEFAULT_RETRY: u64 = 5000;
+const DEFAULT_MAX_DELAY: u64 = 60_000;
+
+/// Granularity at which a blocking reconnect delay is chunked so an `AbortHandle::abort()` call
+/// from another thread is noticed promptly instead of only once the full delay has elapsed.
+const ABORT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum number of redirects followed for a single connection attempt, per the HTML
+/// reconnection model, before giving up with `ErrorKind::TooManyRedirects`.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Outcome of a single bounded line read: a complete line, an unterminated final line or a clean
+/// EOF, or a line that grew past `max_line_size` before a newline was ever seen.
+enum LineOutcome {
+    /// A complete line, ending in `\n`, is in `line`.
+    Line,
+    /// The peer closed the connection mid-line: `line` holds whatever was sent with no
+    /// terminating `\n`. Matches `BufRead::read_line`, which likewise returns the partial bytes
+    /// instead of discarding them.
+    PartialAtEof,
+    /// EOF with nothing buffered; `line` is empty.
+    Eof,
+    TooLarge,
+}
+
+/// Reads a line into `line`, same as `BufRead::read_line`, except the size check runs against
+/// each chunk as it arrives from the underlying reader instead of only once a full line (ending
+/// in `\n`) has been assembled. This keeps a peer that never sends a newline from growing `line`
+/// without bound before `max_line_size` ever gets a chance to fire. Unlike `read_line`, invalid
+/// UTF-8 is replaced with U+FFFD rather than returned as an error.
+fn read_line_bounded<R: ::std::io::Read>(
+    reader: &mut BufReader<R>,
+    line: &mut String,
+    max_line_size: Option<usize>,
+) -> Result<LineOutcome> {
+    // Bytes are collected first and decoded once, so a multi-byte character split across two
+    // buffer refills is decoded whole.
+    let mut bytes = Vec::new();
+    let outcome = loop {
+        let (found, used) = {
+            let available = reader.fill_buf()?;
+            match available.iter().position(|&b| b == b'\n') {
+                Some(pos) => {
+                    bytes.extend_from_slice(&available[..=pos]);
+                    (true, pos + 1)
+                }
+                None => {
+                    bytes.extend_from_slice(available);
+                    (false, available.len())
+                }
+            }
+        };
+        reader.consume(used);
+
+        if max_line_size.map_or(false, |max| line.len() + bytes.len() > max) {
+            break LineOutcome::TooLarge;
+        }
+        if found {
+            break LineOutcome::Line;
+        }
+        if used == 0 {
+            break if line.is_empty() && bytes.is_empty() {
+                LineOutcome::Eof
+            } else {
+                LineOutcome::PartialAtEof
+            };
+        }
+    };
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(outcome)
+}
+
+/// Resolves a redirect response's `Location` header against `base`.
+fn resolve_location(headers: &Headers, base: &reqw::Url) -> Result<reqw::Url> {
+    use self::reqw::header::Location;
+
+    match headers.get::<Location>() {
+        Some(location) => base
+            .join(location)
+            .map_err(|_| ErrorKind::InvalidRedirect.into()),
+        None => Err(ErrorKind::InvalidRedirect.into()),
+    }
+}
+
+/// Whether a failure should trigger a reconnection attempt or stop the stream for good.
+///
+/// Permanent failures (like a `404`) are returned to the caller once; transient ones (timeouts,
+/// connection resets, `429`, `500`, `502`, `503` and `504`) are retried according to the client's
+/// [`RetryPolicy`](struct.RetryPolicy.html). Other `5xx` statuses are permanent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorClass {
+    Transient,
+    Permanent,
+}
+
+fn classify_status(status: reqw::StatusCode) -> ErrorClass {
+    match status.as_u16() {
+        429 | 500 | 502 | 503 | 504 => ErrorClass::Transient,
+        _ => ErrorClass::Permanent,
+    }
+}
+
+fn classify_error(err: &Error) -> ErrorClass {
+    match *err.kind() {
+        ErrorKind::Reqwest(ref e) => {
+            if e.is_timeout() || e.is_connect() {
+                ErrorClass::Transient
+            } else {
+                ErrorClass::Permanent
+            }
+        }
+        ErrorKind::Io(_) => ErrorClass::Transient,
+        ErrorKind::Http(status) => classify_status(status),
+        ErrorKind::InvalidContentType(_) | ErrorKind::NoContentType => ErrorClass::Permanent,
+        _ => ErrorClass::Permanent,
+    }
+}
+
+fn retry_after(headers: &Headers) -> Option<Duration> {
+    match headers.get::<RetryAfter>() {
+        Some(&RetryAfter::Delay(d)) => Some(d),
+        _ => None,
+    }
+}
+
+/// Controls how long a client waits between reconnection attempts after a transient failure.
+///
+/// The delay is `min(base * 2^attempt, max_delay)`, where `base` is the client's `retry` field
+/// (itself overridable by the server via the `Retry-After` header or an SSE `retry:` field) and
+/// `attempt` is zero for the first retry after a failure, one for the second, and so on.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The cap on the backoff delay, regardless of how many attempts have failed. Jitter is
+    /// applied after capping, so with `jitter` above zero the actual delay can exceed it.
+    pub max_delay: Duration,
+    /// Randomized jitter applied to the computed delay, as a fraction of it (e.g. `0.2` for
+    /// +/-20%). Zero disables jitter.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// An exponential backoff policy capped at `max_delay`, with no jitter.
+    pub fn exponential(max_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_delay: max_delay,
+            jitter: 0.0,
+        }
+    }
+
+    fn delay(&self, base: Duration, attempt: u32) -> Duration {
+        let scaled = base
+            .checked_mul(1 << attempt.min(31))
+            .unwrap_or(self.max_delay);
+        let delay = if scaled > self.max_delay {
+            self.max_delay
+        } else {
+            scaled
+        };
+
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+
+        let millis = delay.as_millis() as u64;
+        let spread = (millis as f64 * self.jitter) as u64;
+        if spread == 0 {
+            return delay;
+        }
+
+        let offset = self::rand::thread_rng().gen_range(0, spread * 2 + 1);
+        Duration::from_millis((millis + offset).saturating_sub(spread))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::exponential(Duration::from_millis(DEFAULT_MAX_DELAY))
+    }
+}
+
+/// What a connection attempt resulted in, so the caller can tell a permanent stop from a
+/// connection that's ready to be read.
+enum ConnectOutcome {
+    /// The response is live and has been stashed in `self.response`.
+    Connected,
+    /// The server responded `204 No Content`; reconnection must stop for good.
+    StopPermanently,
+}
 
 /// A client for a Server-Sent Events endpoint.
 ///
@@ -46,14 +367,37 @@ pub struct Client {
     url: reqw::Url,
     last_event_id: Option<String>,
     last_try: Option<Instant>,
+    attempt: u32,
+    stopped: bool,
 
     /// Reconnection time in milliseconds. Note that the reconnection time can be changed by the
     /// event stream, so changing this may not make a difference.
     pub retry: Duration,
 
+    /// Policy controlling the delay between reconnection attempts after a transient failure.
+    pub retry_policy: RetryPolicy,
+
+    /// Maximum number of consecutive transient failures to retry before giving up and ending the
+    /// iterator. `None` (the default) retries forever.
+    pub max_retries: Option<u32>,
+
+    /// Maximum size in bytes of a single field line. Lines larger than this produce
+    /// `ErrorKind::EventTooLarge` instead of growing without bound. `None` means unlimited.
+    pub max_line_size: Option<usize>,
+
+    /// Maximum total size in bytes of the field lines making up a single pending event. Events
+    /// larger than this produce `ErrorKind::EventTooLarge`. `None` means unlimited.
+    pub max_event_size: Option<usize>,
+
     /// Default headers that should be applied to requests. If the conflict with per-request
     /// headers they will be overwritten.
     pub default_headers: Headers,
+
+    /// Where the last dispatched event's `id` is persisted, so it can seed `Last-Event-ID` again
+    /// after a restart. Defaults to an in-memory store, i.e. no persistence across restarts.
+    pub event_id_store: Box<dyn EventIdStore>,
+
+    abort: AbortHandle,
 }
 
 impl Client {
@@ -61,55 +405,128 @@ impl Client {
     ///
     /// This does not start an HTTP request.
     pub fn new(url: reqw::Url) -> Client {
+        Client::with_reqwest_client(reqw::Client::new(), url)
+    }
+
+    /// Constructs a new EventSource client whose underlying HTTP client gives up on a connect or
+    /// read that stalls for longer than `timeout`, surfacing it as a transient
+    /// `reqwest::Error` instead of hanging forever.
+    pub fn with_timeout(url: reqw::Url, timeout: Duration) -> Result<Client> {
+        let client = reqw::Client::builder().timeout(timeout).build()?;
+        Ok(Client::with_reqwest_client(client, url))
+    }
+
+    fn with_reqwest_client(client: reqw::Client, url: reqw::Url) -> Client {
         Client {
-            client: reqw::Client::new(),
+            client: client,
             response: None,
             url: url,
             last_event_id: None,
             last_try: None,
+            attempt: 0,
+            stopped: false,
             retry: Duration::from_millis(DEFAULT_RETRY),
+            retry_policy: RetryPolicy::default(),
+            max_retries: None,
+            max_line_size: None,
+            max_event_size: None,
             default_headers: Headers::new(),
+            event_id_store: Box::new(MemoryEventIdStore::new()),
+            abort: AbortHandle::new(),
         }
     }
 
-    fn next_request(&mut self) -> Result<()> {
-        let mut headers = self.default_headers.clone();
-        headers.set(Accept(vec![qitem(mime::TEXT_EVENT_STREAM)]));
-        if let Some(ref id) = self.last_event_id {
-            headers.set_raw("Last-Event-ID", vec![id.as_bytes().to_vec()]);
+    /// Returns a cloneable handle that can abort this client's iterator from another thread.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// Connects to `self.url`, following redirects and honoring `204` per the HTML reconnection
+    /// model. Returns `ConnectOutcome::StopPermanently` for a `204`, so the caller can stop the
+    /// iterator for good instead of retrying.
+    fn next_request(&mut self) -> Result<ConnectOutcome> {
+        if self.abort.is_aborted() {
+            return Err(ErrorKind::Aborted.into());
         }
 
-        let res = self.client.get(self.url.clone()).headers(headers).send()?;
+        // Seed from the persisted store while nothing is known yet; once the store or a
+        // dispatched event has supplied an id, `last_event_id` is used as is.
+        if self.last_event_id.is_none() {
+            self.last_event_id = self.event_id_store.load();
+        }
 
-        // Check status code and Content-Type.
-        {
+        let mut url = self.url.clone();
+        let mut redirects = 0u32;
+
+        loop {
+            let mut headers = self.default_headers.clone();
+            headers.set(Accept(vec![qitem(mime::TEXT_EVENT_STREAM)]));
+            if let Some(ref id) = self.last_event_id {
+                headers.set_raw("Last-Event-ID", vec![id.as_bytes().to_vec()]);
+            }
+
+            let res = self.client.get(url.clone()).headers(headers).send()?;
             let status = res.status();
-            if !status.is_success() {
-                return Err(ErrorKind::Http(status.clone()).into());
-            }
-            if let Some(&ContentType(ref content_type)) = res.headers().get::<ContentType>() {
-                // Compare type and subtype only, MIME parameters are ignored.
-                if (content_type.type_(), content_type.subtype()) !=
-                    (mime::TEXT, mime::EVENT_STREAM)
-                {
-                    return Err(ErrorKind::InvalidContentType(content_type.clone()).into());
+
+            match status.as_u16() {
+                // 204 No Content permanently stops reconnection.
+                204 => return Ok(ConnectOutcome::StopPermanently),
+                // 301/307 update the client's URL and reconnect there.
+                301 | 307 => {
+                    redirects += 1;
+                    if redirects > MAX_REDIRECTS {
+                        return Err(ErrorKind::TooManyRedirects.into());
+                    }
+                    url = resolve_location(res.headers(), &url)?;
+                    self.url = url.clone();
+                    continue;
+                }
+                // 302/303 reconnect to the new location without persisting it.
+                302 | 303 => {
+                    redirects += 1;
+                    if redirects > MAX_REDIRECTS {
+                        return Err(ErrorKind::TooManyRedirects.into());
+                    }
+                    url = resolve_location(res.headers(), &url)?;
+                    continue;
+                }
+                200..=299 => {
+                    if let Some(&ContentType(ref content_type)) = res.headers().get::<ContentType>()
+                    {
+                        // Compare type and subtype only, MIME parameters are ignored.
+                        if (content_type.type_(), content_type.subtype())
+                            != (mime::TEXT, mime::EVENT_STREAM)
+                        {
+                            return Err(ErrorKind::InvalidContentType(content_type.clone()).into());
+                        }
+                    } else {
+                        return Err(ErrorKind::NoContentType.into());
+                    }
+
+                    self.response = Some(BufReader::new(res));
+                    return Ok(ConnectOutcome::Connected);
+                }
+                // Any other status fails the connection with `ErrorKind::Http`; the caller retries
+                // it if `classify_status` deems it transient (e.g. 500/503) and stops otherwise.
+                _ => {
+                    if let Some(delay) = retry_after(res.headers()) {
+                        self.retry = delay;
+                    }
+                    return Err(ErrorKind::Http(status.clone()).into());
                 }
-            } else {
-                return Err(ErrorKind::NoContentType.into());
             }
         }
-
-        self.response = Some(BufReader::new(res));
-        Ok(())
     }
-}
 
-// Helper macro for Option<Result<...>>
-macro_rules! try_option {
-    ($e:expr) => (match $e {
-        Ok(val) => val,
-        Err(err) => return Some(Err(::std::convert::From::from(err))),
-    });
+    /// Returns `true` if a transient failure should trigger another retry, bumping the internal
+    /// attempt counter. Returns `false` once `max_retries` has been exceeded.
+    fn should_retry(&mut self) -> bool {
+        self.attempt += 1;
+        match self.max_retries {
+            Some(max) => self.attempt <= max,
+            None => true,
+        }
+    }
 }
 
 /// Iterate over the client to get events.
@@ -119,58 +536,639 @@ impl Iterator for Client {
     type Item = Result<Event>;
 
     fn next(&mut self) -> Option<Result<Event>> {
-        if self.response.is_none() {
-            // We may have to wait for the next request.
-            if let Some(last_try) = self.last_try {
-                let elapsed = last_try.elapsed();
-                if elapsed < self.retry {
-                    ::std::thread::sleep(self.retry - elapsed);
+        // Loops on transient failures instead of recursing, so a fast-failing endpoint can't
+        // overflow the stack.
+        loop {
+            if self.stopped || self.abort.is_aborted() {
+                return None;
+            }
+
+            if self.response.is_none() {
+                // We may have to wait for the next request. Sleep in small steps so an abort
+                // from another thread is noticed promptly instead of after the full delay.
+                if let Some(last_try) = self.last_try {
+                    // `should_retry` already bumped `attempt` for the failure we're about to wait
+                    // out, so subtract one to get back the 0-based attempt number it represents.
+                    let delay = self.retry_policy.delay(self.retry, self.attempt - 1);
+                    let mut remaining = delay.checked_sub(last_try.elapsed()).unwrap_or_default();
+                    while remaining > Duration::from_millis(0) {
+                        if self.abort.is_aborted() {
+                            return None;
+                        }
+                        let step = ABORT_POLL_INTERVAL.min(remaining);
+                        ::std::thread::sleep(step);
+                        remaining -= step;
+                    }
+                }
+                // Set here in case the request fails.
+                self.last_try = Some(Instant::now());
+
+                match self.next_request() {
+                    Ok(ConnectOutcome::Connected) => (),
+                    Ok(ConnectOutcome::StopPermanently) => {
+                        self.stopped = true;
+                        return None;
+                    }
+                    Err(err) => {
+                        if self.abort.is_aborted() {
+                            return None;
+                        }
+                        match classify_error(&err) {
+                            ErrorClass::Transient if self.should_retry() => continue,
+                            _ => {
+                                self.stopped = true;
+                                return Some(Err(err));
+                            }
+                        }
+                    }
                 }
             }
-            // Set here in case the request fails.
+
+            let result = {
+                let mut event = Event::new();
+                let mut event_size = 0usize;
+                let mut line = String::new();
+                let reader = self.response.as_mut().unwrap();
+
+                loop {
+                    if self.abort.is_aborted() {
+                        return None;
+                    }
+
+                    match read_line_bounded(reader, &mut line, self.max_line_size) {
+                        Ok(LineOutcome::TooLarge) => {
+                            break Some(Err(ErrorKind::EventTooLarge.into()));
+                        }
+                        // A trailing, unterminated line at EOF is still fed to the parser, same
+                        // as a complete one; the next call around the loop will see a true EOF.
+                        Ok(LineOutcome::Line) | Ok(LineOutcome::PartialAtEof) => {
+                            event_size += line.len();
+                            if self.max_event_size.map_or(false, |max| event_size > max) {
+                                break Some(Err(ErrorKind::EventTooLarge.into()));
+                            }
+
+                            match parse_event_line(&line, &mut event) {
+                                ParseResult::Next => (), // okay, just continue
+                                ParseResult::Dispatch => {
+                                    if let Some(ref id) = event.id {
+                                        self.last_event_id = Some(id.clone());
+                                        self.event_id_store.store(id);
+                                    }
+                                    self.attempt = 0;
+                                    return Some(Ok(event));
+                                }
+                                ParseResult::SetRetry(ref retry) => {
+                                    self.retry = *retry;
+                                }
+                            }
+                            line.clear();
+                        }
+                        // Clean EOF
+                        Ok(LineOutcome::Eof) => break None,
+                        Err(err) => break Some(Err(err)),
+                    }
+                }
+            };
+
+            // EOF or a stream error, retry after timeout
             self.last_try = Some(Instant::now());
+            self.response = None;
+
+            match result {
+                // Clean EOF, e.g. the server simply closed the connection. This is the common
+                // case, and still needs to go through the same attempt/`max_retries` accounting
+                // as a transient error, or `max_retries` would never bound ordinary disconnects.
+                None => {
+                    if self.should_retry() {
+                        continue;
+                    }
+                    self.stopped = true;
+                    return None;
+                }
+                Some(Err(err)) => match classify_error(&err) {
+                    ErrorClass::Transient if self.should_retry() => continue,
+                    _ => {
+                        self.stopped = true;
+                        return Some(Err(err));
+                    }
+                },
+            }
+        }
+    }
+}
 
-            try_option!(self.next_request());
+type ResponseFuture = Pin<
+    Box<dyn Future<Output = self::reqw::Result<self::reqw::r#async::Response>> + Send>,
+>;
+type ChunkStream = Pin<Box<dyn Stream<Item = self::reqw::Result<self::bytes::Bytes>> + Send>>;
+
+/// Internal state driving `AsyncClient::poll_next`.
+enum AsyncState {
+    /// No request has been issued yet. Deferring this to the first poll, rather than building it
+    /// in `AsyncClient::new`, means the request is built from whatever `event_id_store` and
+    /// `default_headers` the caller has installed by then, not the defaults `new` started with.
+    NotStarted,
+    /// Waiting out the reconnection delay before the next request is issued.
+    Waiting(Delay),
+    /// The HTTP request for the next response is in flight.
+    Connecting(ResponseFuture),
+    /// Reading chunks from a live response body.
+    Streaming(ChunkStream),
+    /// A permanent failure, or too many transient ones, ended the stream for good.
+    Done,
+}
+
+fn start_request(
+    client: &reqw::r#async::Client,
+    url: &reqw::Url,
+    last_event_id: &Option<String>,
+    default_headers: &Headers,
+) -> ResponseFuture {
+    let mut headers = default_headers.clone();
+    headers.set(Accept(vec![qitem(mime::TEXT_EVENT_STREAM)]));
+    if let Some(ref id) = *last_event_id {
+        headers.set_raw("Last-Event-ID", vec![id.as_bytes().to_vec()]);
+    }
+
+    Box::pin(client.get(url.clone()).headers(headers).send())
+}
+
+fn check_response(res: &reqw::r#async::Response, retry: &mut Duration) -> Option<Error> {
+    let status = res.status();
+    if !status.is_success() {
+        if let Some(delay) = retry_after(res.headers()) {
+            *retry = delay;
+        }
+        return Some(ErrorKind::Http(status.clone()).into());
+    }
+    match res.headers().get::<ContentType>() {
+        Some(&ContentType(ref content_type)) => {
+            if (content_type.type_(), content_type.subtype()) != (mime::TEXT, mime::EVENT_STREAM) {
+                return Some(ErrorKind::InvalidContentType(content_type.clone()).into());
+            }
+            None
+        }
+        None => Some(ErrorKind::NoContentType.into()),
+    }
+}
+
+/// Feeds complete lines out of `buf` to the event parser, stopping at the first dispatched event
+/// or the first line/event that exceeds the configured size limits. Any trailing partial line is
+/// left in `buf` for the next poll, but is still checked against `max_line_size` so it can't grow
+/// without bound while waiting for a newline that may never arrive.
+#[allow(clippy::too_many_arguments)]
+fn drain_buf(
+    buf: &mut Vec<u8>,
+    event: &mut Event,
+    event_size: &mut usize,
+    retry: &mut Duration,
+    max_line_size: Option<usize>,
+    max_event_size: Option<usize>,
+) -> Option<Result<Event>> {
+    while let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+        let line = buf.drain(..=pos).collect::<Vec<u8>>();
+        if max_line_size.map_or(false, |max| line.len() > max) {
+            return Some(Err(ErrorKind::EventTooLarge.into()));
+        }
+        *event_size += line.len();
+        if max_event_size.map_or(false, |max| *event_size > max) {
+            return Some(Err(ErrorKind::EventTooLarge.into()));
+        }
+        let line = String::from_utf8_lossy(&line);
+
+        match parse_event_line(&line, event) {
+            ParseResult::Next => (), // okay, just continue
+            ParseResult::Dispatch => {
+                *event_size = 0;
+                return Some(Ok(mem::replace(event, Event::new())));
+            }
+            ParseResult::SetRetry(ref new_retry) => *retry = *new_retry,
         }
+    }
+
+    // No complete line yet. Check the partial line buffered so far against the limit too, so a
+    // peer that never sends a newline can't grow `buf` without bound across polls while waiting
+    // for one.
+    if max_line_size.map_or(false, |max| buf.len() > max) {
+        return Some(Err(ErrorKind::EventTooLarge.into()));
+    }
+
+    None
+}
+
+/// An async, `Stream`-based client for a Server-Sent Events endpoint.
+///
+/// Unlike [`Client`](struct.Client.html), which blocks a thread per stream, `AsyncClient` drives
+/// the connection and the reconnection delay entirely through polling, so many streams can be
+/// multiplexed on one executor.
+pub struct AsyncClient {
+    client: reqw::r#async::Client,
+    state: AsyncState,
+    url: reqw::Url,
+    last_event_id: Option<String>,
+    buf: Vec<u8>,
+    event: Event,
+    event_size: usize,
+    attempt: u32,
+    redirects: u32,
+
+    /// Reconnection time. Note that the reconnection time can be changed by the event stream, so
+    /// changing this may not make a difference.
+    pub retry: Duration,
+
+    /// Policy controlling the delay between reconnection attempts after a transient failure.
+    pub retry_policy: RetryPolicy,
+
+    /// Maximum number of consecutive transient failures to retry before giving up and ending the
+    /// stream. `None` (the default) retries forever.
+    pub max_retries: Option<u32>,
+
+    /// Maximum size in bytes of a single field line. Lines larger than this produce
+    /// `ErrorKind::EventTooLarge` instead of growing without bound. `None` means unlimited.
+    pub max_line_size: Option<usize>,
+
+    /// Maximum total size in bytes of the field lines making up a single pending event. Events
+    /// larger than this produce `ErrorKind::EventTooLarge`. `None` means unlimited.
+    pub max_event_size: Option<usize>,
+
+    /// Default headers that should be applied to requests. If they conflict with per-request
+    /// headers they will be overwritten.
+    pub default_headers: Headers,
+
+    /// Where the last dispatched event's `id` is persisted, so it can seed `Last-Event-ID` again
+    /// after a restart. Defaults to an in-memory store, i.e. no persistence across restarts.
+    pub event_id_store: Box<dyn EventIdStore>,
+
+    abort: AbortHandle,
+}
+
+impl AsyncClient {
+    /// Constructs a new async EventSource client for the given URL.
+    ///
+    /// The first request is issued on the first poll.
+    pub fn new(url: reqw::Url) -> AsyncClient {
+        AsyncClient {
+            client: reqw::r#async::Client::new(),
+            state: AsyncState::NotStarted,
+            url: url,
+            last_event_id: None,
+            buf: Vec::new(),
+            event: Event::new(),
+            event_size: 0,
+            attempt: 0,
+            redirects: 0,
+            retry: Duration::from_millis(DEFAULT_RETRY),
+            retry_policy: RetryPolicy::default(),
+            max_retries: None,
+            max_line_size: None,
+            max_event_size: None,
+            default_headers: Headers::new(),
+            event_id_store: Box::new(MemoryEventIdStore::new()),
+            abort: AbortHandle::new(),
+        }
+    }
+
+    /// Returns a cloneable handle that can abort this client's stream from another thread or
+    /// task.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// Returns `true` if a transient failure should trigger another retry, bumping the internal
+    /// attempt counter. Returns `false` once `max_retries` has been exceeded.
+    fn should_retry(&mut self) -> bool {
+        self.attempt += 1;
+        match self.max_retries {
+            Some(max) => self.attempt <= max,
+            None => true,
+        }
+    }
+}
+
+/// Poll the client to get events.
+///
+/// HTTP requests and reconnection delays are driven transparently while polling.
+impl Stream for AsyncClient {
+    type Item = Result<Event>;
 
-        let result = {
-            let mut event = Event::new();
-            let mut line = String::new();
-            let reader = self.response.as_mut().unwrap();
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-            loop {
-                match reader.read_line(&mut line) {
-                    // Got new bytes from stream
-                    Ok(_n) if _n > 0 => {
-                        match parse_event_line(&line, &mut event) {
-                            ParseResult::Next => (), // okay, just continue
-                            ParseResult::Dispatch => {
-                                if let Some(ref id) = event.id {
-                                    self.last_event_id = Some(id.clone());
+        loop {
+            if this.abort.is_aborted() {
+                this.state = AsyncState::Done;
+                return Poll::Ready(None);
+            }
+
+            match this.state {
+                AsyncState::Done => return Poll::Ready(None),
+                AsyncState::NotStarted => {
+                    // Seed from the persisted store here, on first poll, rather than in `new`, so
+                    // a store installed after construction (the only way to install one, since
+                    // `event_id_store` is a plain field) is what the first request actually uses.
+                    if this.last_event_id.is_none() {
+                        this.last_event_id = this.event_id_store.load();
+                    }
+                    let fut = start_request(
+                        &this.client,
+                        &this.url,
+                        &this.last_event_id,
+                        &this.default_headers,
+                    );
+                    this.state = AsyncState::Connecting(fut);
+                }
+                AsyncState::Waiting(ref mut delay) => match Pin::new(delay).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(_) => {
+                        this.redirects = 0;
+                        let fut = start_request(
+                            &this.client,
+                            &this.url,
+                            &this.last_event_id,
+                            &this.default_headers,
+                        );
+                        this.state = AsyncState::Connecting(fut);
+                    }
+                },
+                AsyncState::Connecting(ref mut fut) => match fut.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(res)) => {
+                        match res.status().as_u16() {
+                            // 204 No Content permanently stops reconnection.
+                            204 => {
+                                this.state = AsyncState::Done;
+                                return Poll::Ready(None);
+                            }
+                            // 301/307 update the client's URL and reconnect there; 302/303
+                            // reconnect to the new location without persisting it.
+                            status @ (301 | 307 | 302 | 303) => {
+                                this.redirects += 1;
+                                if this.redirects > MAX_REDIRECTS {
+                                    this.state = AsyncState::Done;
+                                    return Poll::Ready(Some(Err(ErrorKind::TooManyRedirects.into())));
+                                }
+                                match resolve_location(res.headers(), &this.url) {
+                                    Ok(location) => {
+                                        if status == 301 || status == 307 {
+                                            this.url = location.clone();
+                                        }
+                                        this.state = AsyncState::Connecting(start_request(
+                                            &this.client,
+                                            &location,
+                                            &this.last_event_id,
+                                            &this.default_headers,
+                                        ));
+                                    }
+                                    Err(err) => {
+                                        this.state = AsyncState::Done;
+                                        return Poll::Ready(Some(Err(err)));
+                                    }
                                 }
-                                return Some(Ok(event));
                             }
-                            ParseResult::SetRetry(ref retry) => {
-                                self.retry = *retry;
+                            _ => match check_response(&res, &mut this.retry) {
+                                Some(err) => {
+                                    if classify_error(&err) == ErrorClass::Transient
+                                        && this.should_retry()
+                                    {
+                                        this.state = AsyncState::Waiting(delay_for(
+                                            this.retry_policy.delay(this.retry, this.attempt - 1),
+                                        ));
+                                    } else {
+                                        this.state = AsyncState::Done;
+                                    }
+                                    return Poll::Ready(Some(Err(err)));
+                                }
+                                None => {
+                                    this.attempt = 0;
+                                    this.buf.clear();
+                                    this.state = AsyncState::Streaming(Box::pin(res.bytes_stream()));
+                                }
+                            },
+                        }
+                    }
+                    Poll::Ready(Err(err)) => {
+                        let err: Error = err.into();
+                        if classify_error(&err) == ErrorClass::Transient && this.should_retry() {
+                            this.state = AsyncState::Waiting(delay_for(
+                                this.retry_policy.delay(this.retry, this.attempt - 1),
+                            ));
+                        } else {
+                            this.state = AsyncState::Done;
+                        }
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                },
+                AsyncState::Streaming(ref mut stream) => {
+                    match drain_buf(
+                        &mut this.buf,
+                        &mut this.event,
+                        &mut this.event_size,
+                        &mut this.retry,
+                        this.max_line_size,
+                        this.max_event_size,
+                    ) {
+                        Some(Ok(event)) => {
+                            if let Some(ref id) = event.id {
+                                this.last_event_id = Some(id.clone());
+                                this.event_id_store.store(id);
+                            }
+                            this.attempt = 0;
+                            return Poll::Ready(Some(Ok(event)));
+                        }
+                        Some(Err(err)) => {
+                            if classify_error(&err) == ErrorClass::Transient && this.should_retry()
+                            {
+                                this.state = AsyncState::Waiting(delay_for(
+                                    this.retry_policy.delay(this.retry, this.attempt - 1),
+                                ));
+                            } else {
+                                this.state = AsyncState::Done;
+                            }
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                        None => {}
+                    }
+
+                    match stream.as_mut().poll_next(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Some(Ok(chunk))) => this.buf.extend_from_slice(&chunk),
+                        Poll::Ready(Some(Err(err))) => {
+                            let err: Error = err.into();
+                            if classify_error(&err) == ErrorClass::Transient && this.should_retry() {
+                                this.state = AsyncState::Waiting(delay_for(
+                                    this.retry_policy.delay(this.retry, this.attempt - 1),
+                                ));
+                            } else {
+                                this.state = AsyncState::Done;
+                            }
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                        Poll::Ready(None) => {
+                            // EOF is transient: reconnect after the retry delay, carrying over
+                            // last_event_id.
+                            if this.should_retry() {
+                                this.state = AsyncState::Waiting(delay_for(
+                                    this.retry_policy.delay(this.retry, this.attempt - 1),
+                                ));
+                            } else {
+                                this.state = AsyncState::Done;
                             }
                         }
-                        line.clear();
                     }
-                    // Nothing read from stream
-                    Ok(_) => break None,
-                    Err(err) => break Some(Err(::std::convert::From::from(err))),
                 }
             }
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_line_bounded_rejects_a_line_with_no_newline_once_it_exceeds_the_limit() {
+        let data: &[u8] = b"this line never ends and has no newline at all";
+        let mut reader = BufReader::new(data);
+        let mut line = String::new();
+
+        match read_line_bounded(&mut reader, &mut line, Some(8)).unwrap() {
+            LineOutcome::TooLarge => (),
+            _ => panic!("expected LineOutcome::TooLarge"),
+        }
+    }
+
+    #[test]
+    fn read_line_bounded_reads_a_complete_line_under_the_limit() {
+        let data: &[u8] = b"hello\nworld";
+        let mut reader = BufReader::new(data);
+        let mut line = String::new();
+
+        match read_line_bounded(&mut reader, &mut line, Some(4096)).unwrap() {
+            LineOutcome::Line => assert_eq!(line, "hello\n"),
+            _ => panic!("expected LineOutcome::Line"),
+        }
+    }
+
+    #[test]
+    fn read_line_bounded_returns_an_unterminated_final_line_instead_of_discarding_it() {
+        let data: &[u8] = b"no trailing newline";
+        let mut reader = BufReader::new(data);
+        let mut line = String::new();
+
+        match read_line_bounded(&mut reader, &mut line, Some(4096)).unwrap() {
+            LineOutcome::PartialAtEof => assert_eq!(line, "no trailing newline"),
+            _ => panic!("expected LineOutcome::PartialAtEof"),
+        }
+
+        // The next call sees the true EOF, with nothing left buffered.
+        let mut next_line = String::new();
+        match read_line_bounded(&mut reader, &mut next_line, Some(4096)).unwrap() {
+            LineOutcome::Eof => assert!(next_line.is_empty()),
+            _ => panic!("expected LineOutcome::Eof"),
+        }
+    }
+
+    #[test]
+    fn drain_buf_rejects_an_unterminated_line_once_it_exceeds_max_line_size() {
+        let mut buf = b"data: oops".to_vec();
+        let mut event = Event::new();
+        let mut event_size = 0usize;
+        let mut retry = Duration::from_millis(DEFAULT_RETRY);
+
+        let result = drain_buf(&mut buf, &mut event, &mut event_size, &mut retry, Some(4), None);
 
         match result {
-            None | Some(Err(_)) => {
-                // EOF or a stream error, retry after timeout
-                self.last_try = Some(Instant::now());
-                self.response = None;
-                self.next()
-            }
-            _ => result,
+            Some(Err(_)) => (),
+            _ => panic!("expected Some(Err(_))"),
+        }
+    }
+
+    #[test]
+    fn drain_buf_leaves_a_short_partial_line_buffered() {
+        let mut buf = b"data: ok".to_vec();
+        let mut event = Event::new();
+        let mut event_size = 0usize;
+        let mut retry = Duration::from_millis(DEFAULT_RETRY);
+
+        let result = drain_buf(&mut buf, &mut event, &mut event_size, &mut retry, Some(4096), None);
+
+        assert!(result.is_none());
+        assert_eq!(buf, b"data: ok");
+    }
+
+    #[test]
+    fn file_event_id_store_round_trips_through_a_restart() {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("eventsource-test-{}.id", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = FileEventIdStore::new(&path);
+        assert_eq!(store.load(), None);
+
+        store.store("abc123");
+        assert_eq!(store.load(), Some("abc123".to_owned()));
+
+        // Simulates a restart: a fresh store reading the same path picks up the persisted id.
+        let restarted = FileEventIdStore::new(&path);
+        assert_eq!(restarted.load(), Some("abc123".to_owned()));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn retry_policy_delay_is_unscaled_for_the_first_attempt() {
+        let policy = RetryPolicy::exponential(Duration::from_secs(60));
+        let base = Duration::from_millis(100);
+
+        assert_eq!(policy.delay(base, 0), base);
+        assert_eq!(policy.delay(base, 1), base * 2);
+        assert_eq!(policy.delay(base, 2), base * 4);
+    }
+
+    #[test]
+    fn retry_policy_delay_is_capped_at_max_delay() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(500));
+        let base = Duration::from_millis(100);
+
+        assert_eq!(policy.delay(base, 10), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn classify_status_treats_server_overload_codes_as_transient() {
+        assert_eq!(
+            classify_status(reqw::StatusCode::SERVICE_UNAVAILABLE),
+            ErrorClass::Transient
+        );
+        assert_eq!(
+            classify_status(reqw::StatusCode::NOT_FOUND),
+            ErrorClass::Permanent
+        );
+    }
+
+    #[test]
+    fn classify_error_treats_event_too_large_as_permanent() {
+        let err: Error = ErrorKind::EventTooLarge.into();
+        assert_eq!(classify_error(&err), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn resolve_location_joins_a_relative_path_against_the_base() {
+        let base = reqw::Url::parse("https://example.com/events").unwrap();
+        let mut headers = Headers::new();
+        headers.set_raw("Location", vec![b"/events/retry".to_vec()]);
+
+        let resolved = resolve_location(&headers, &base).unwrap();
+        assert_eq!(resolved.as_str(), "https://example.com/events/retry");
+    }
+
+    #[test]
+    fn resolve_location_rejects_a_missing_location_header() {
+        let base = reqw::Url::parse("https://example.com/events").unwrap();
+        let headers = Headers::new();
+
+        match resolve_location(&headers, &base) {
+            Err(_) => (),
+            Ok(_) => panic!("expected an error for a missing Location header"),
         }
     }
 }